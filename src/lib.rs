@@ -1,7 +1,9 @@
 use bip32::{Seed, XPrv};
 use bitcoin::{
-    key::{Secp256k1, UntweakedKeypair},
-    secp256k1::Message,
+    key::{Secp256k1, TapTweak, UntweakedKeypair},
+    secp256k1::{schnorr::Signature, Message, SecretKey, SignOnly, VerifyOnly, XOnlyPublicKey},
+    taproot::TapNodeHash,
+    PublicKey,
 };
 use candid::{CandidType, Decode, Deserialize, Encode, Principal};
 
@@ -11,10 +13,11 @@ use serde_bytes::ByteBuf;
 use ic_crypto_extended_bip32::{DerivationIndex, DerivationPath};
 
 use ic_stable_structures::storable::Bound;
-use ic_stable_structures::{StableBTreeMap, StableCell, Storable};
+use ic_stable_structures::{StableBTreeMap, Storable};
 
 use std::borrow::Cow;
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::time::Duration;
 
 use getrandom::{register_custom_getrandom, Error};
@@ -29,6 +32,23 @@ pub struct SchnorrPublicKey {
     pub canister_id: Option<Principal>,
     pub derivation_path: Vec<Vec<u8>>,
     pub key_id: SchnorrKeyId,
+    /// Requests a BIP341 taproot output key instead of the raw derived key.
+    ///
+    /// Deliberately a nested option rather than a flat `Option<[u8; 32]>`: the
+    /// outer layer opts in to taproot tweaking at all, since plain (untweaked)
+    /// keys are still the common case for this canister and must stay
+    /// reachable. The inner layer then picks which BIP341 variant:
+    ///
+    /// * `None` returns the plain BIP32-derived public key, untweaked.
+    /// * `Some(None)` tweaks it with the empty-merkle-root variant (a
+    ///   key-path-only taproot output, `t = tagged_hash("TapTweak", P)`).
+    /// * `Some(Some(merkle_root))` tweaks it with `t = tagged_hash("TapTweak",
+    ///   P || merkle_root)`, for a taproot output that also commits to a
+    ///   script tree.
+    ///
+    /// Candid has no trouble with this (`opt opt blob`), so it's exposed
+    /// as-is rather than flattened for the public interface.
+    pub merkle_root: Option<Option<[u8; 32]>>,
 }
 
 #[derive(CandidType, Deserialize, Debug)]
@@ -42,6 +62,28 @@ pub struct SignWithSchnorr {
     pub message: Vec<u8>,
     pub derivation_path: Vec<Vec<u8>>,
     pub key_id: SchnorrKeyId,
+    /// Optional BIP340 auxiliary randomness, XORed into nonce derivation.
+    ///
+    /// `None` signs deterministically (`sign_schnorr_no_aux_rand`); `Some`
+    /// picks where the 32 bytes come from. This is an enum rather than a
+    /// plain `Option<[u8; 32]>` with a sentinel value so that every possible
+    /// 32-byte value — including all-zero, which is a legal aux_rand value —
+    /// stays available to callers who want to supply it explicitly.
+    pub aux_randomness: Option<AuxRandomness>,
+    /// Requests signing with a BIP341-tweaked taproot output key rather than
+    /// the raw derived key. See [`SchnorrPublicKey::merkle_root`] for the
+    /// meaning of `None` / `Some(None)` / `Some(Some(merkle_root))`.
+    pub merkle_root: Option<Option<[u8; 32]>>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug)]
+pub enum AuxRandomness {
+    /// Use these 32 bytes as the BIP340 auxiliary randomness directly.
+    Provided([u8; 32]),
+    /// Ask the canister to source the 32 bytes itself from `raw_rand`, so
+    /// callers get side-channel hardening without having to provide their
+    /// own entropy.
+    FromCanister,
 }
 
 pub enum SchnorrKeyIds {
@@ -70,6 +112,56 @@ pub struct SignWithSchnorrReply {
     pub signature: Vec<u8>,
 }
 
+#[derive(CandidType, Deserialize, Serialize, Debug)]
+pub struct SignWithEcdsa {
+    pub message: Vec<u8>,
+    pub derivation_path: Vec<Vec<u8>>,
+    pub key_id: SchnorrKeyId,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct SignWithEcdsaReply {
+    pub signature: Vec<u8>,
+    pub recovery_id: i32,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug)]
+pub struct VerifySchnorr {
+    pub message: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub public_key: Vec<u8>,
+    // Kept alongside the other fields for API symmetry with
+    // `schnorr_public_key`/`sign_with_schnorr`; BIP340 verification does not
+    // branch on it today, but it's here if we ever need to pick a
+    // key-id-specific verification context.
+    pub key_id: SchnorrKeyId,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct VerifySchnorrReply {
+    pub is_signature_valid: bool,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug)]
+pub struct FindDerivationWithPrefix {
+    pub key_id: SchnorrKeyId,
+    pub base_path: Vec<Vec<u8>>,
+    pub prefix: Vec<u8>,
+    pub max_iters: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct FindDerivationWithPrefixReply {
+    pub index: u64,
+    pub public_key: Vec<u8>,
+    pub chain_code: Vec<u8>,
+}
+
+/// Upper bound on `FindDerivationWithPrefix::max_iters`, so a careless or
+/// adversarial caller can't burn an unbounded number of instructions
+/// searching for a prefix that will never show up.
+const MAX_VANITY_SEARCH_ITERS: u64 = 100_000;
+
 #[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct SchnorrKeyId {
     name: String,
@@ -110,7 +202,9 @@ struct HttpResponse {
 #[derive(Serialize, Deserialize)]
 struct Metrics {
     pub balance: u128,
-    pub sig_count: u128,
+    // Keyed by `SchnorrKeyId::name` so each key id's usage is visible
+    // independently (e.g. `dfx_test_key` vs `test_key_1`).
+    pub sig_count: BTreeMap<String, u128>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -119,14 +213,30 @@ struct State {
     #[serde(skip, default = "init_stable_data")]
     seeds: StableBTreeMap<SchnorrKeyId, [u8; 64], Memory>,
 
+    // Signature count per key id, stored in a stable memory.
     #[serde(skip, default = "init_sig_count")]
-    sig_count: StableCell<u128, Memory>,
+    sig_count: StableBTreeMap<SchnorrKeyId, u128, Memory>,
 }
 
 thread_local! {
     static STATE: RefCell<State> = RefCell::new(State::default());
 }
 
+/// Capability-scoped secp256k1 contexts, built once per replica and reused
+/// across calls instead of calling `Secp256k1::new()` (which randomizes a
+/// full signing+verification context) on every request.
+struct Secp256k1Contexts {
+    signing: Secp256k1<SignOnly>,
+    verification: Secp256k1<VerifyOnly>,
+}
+
+thread_local! {
+    static CONTEXTS: Secp256k1Contexts = Secp256k1Contexts {
+        signing: Secp256k1::signing_only(),
+        verification: Secp256k1::verification_only(),
+    };
+}
+
 #[ic_cdk::init]
 fn init() {
     ic_cdk_timers::set_timer(Duration::ZERO, || {
@@ -145,8 +255,6 @@ fn init() {
 }
 #[ic_cdk::update]
 fn schnorr_public_key(arg: SchnorrPublicKey) -> SchnorrPublicKeyReply {
-    let secp256k1: Secp256k1<bitcoin::secp256k1::All> = Secp256k1::new();
-
     let seed = Seed::new(STATE.with(|s| {
         s.borrow()
             .seeds
@@ -158,7 +266,8 @@ fn schnorr_public_key(arg: SchnorrPublicKey) -> SchnorrPublicKeyReply {
     let root_xprv = XPrv::new(&seed).unwrap();
     let key_bytes = root_xprv.private_key().to_bytes();
 
-    let key_pair = UntweakedKeypair::from_seckey_slice(&secp256k1, &key_bytes)
+    let key_pair = CONTEXTS
+        .with(|c| UntweakedKeypair::from_seckey_slice(&c.signing, &key_bytes))
         .expect("Should generate key pair");
 
     let master_chain_code = [0u8; 32];
@@ -182,18 +291,42 @@ fn schnorr_public_key(arg: SchnorrPublicKey) -> SchnorrPublicKeyReply {
         .key_derivation(&public_key_sec1, &master_chain_code)
         .expect("Should derive key");
 
+    let public_key = match arg.merkle_root {
+        Some(merkle_root) => {
+            let internal_key: XOnlyPublicKey = PublicKey::from_slice(&res.derived_public_key)
+                .expect("Should parse derived public key")
+                .into();
+            let merkle_root = merkle_root.map(TapNodeHash::assume_hidden);
+
+            CONTEXTS.with(|c| {
+                internal_key
+                    .tap_tweak(&c.verification, merkle_root)
+                    .0
+                    .serialize()
+                    .to_vec()
+            })
+        }
+        None => res.derived_public_key,
+    };
+
     SchnorrPublicKeyReply {
-        public_key: res.derived_public_key,
+        public_key,
         chain_code: res.derived_chain_code,
     }
 }
 
 #[ic_cdk::update]
-fn sign_with_schnorr(arg: SignWithSchnorr) -> SignWithSchnorrReply {
-    
+async fn sign_with_schnorr(arg: SignWithSchnorr) -> SignWithSchnorrReply {
+
 
     let message = arg.message;
 
+    let aux_randomness = match arg.aux_randomness {
+        Some(AuxRandomness::Provided(bytes)) => Some(bytes),
+        Some(AuxRandomness::FromCanister) => Some(get_canister_aux_rand().await),
+        None => None,
+    };
+
     let seed = Seed::new(STATE.with(|s| {
         s.borrow()
             .seeds
@@ -202,11 +335,11 @@ fn sign_with_schnorr(arg: SignWithSchnorr) -> SignWithSchnorrReply {
             .clone()
     }));
 
-    // Increment the signature count
+    // Increment the signature count for this key id.
     STATE.with(|s| {
         let mut state = s.borrow_mut();
-        let current_count = state.sig_count.get().clone();
-        let _ = state.sig_count.set(current_count + 1);
+        let current_count = state.sig_count.get(&arg.key_id).unwrap_or(0);
+        state.sig_count.insert(arg.key_id.clone(), current_count + 1);
     });
     
     let root_xprv = XPrv::new(&seed).unwrap();
@@ -229,26 +362,212 @@ fn sign_with_schnorr(arg: SignWithSchnorr) -> SignWithSchnorrReply {
         .private_key_derivation(&private_key_bytes, &master_chain_code)
         .expect("Should derive key");
 
-    let secp256k1: Secp256k1<bitcoin::secp256k1::All> = Secp256k1::new();
-    let key_pair = UntweakedKeypair::from_seckey_slice(&secp256k1, &res.derived_private_key)
-        .expect("Should generate key pair");
+    let digest = Message::from_digest_slice(message.as_ref())
+        .expect("should be cryptographically secure hash");
+
+    let merkle_root = arg.merkle_root.map(|root| root.map(TapNodeHash::assume_hidden));
 
-    let sig = secp256k1.sign_schnorr_no_aux_rand(
-        &Message::from_digest_slice(message.as_ref())
-            .expect("should be cryptographically secure hash"),
-        &key_pair,
-    );
+    let sig = CONTEXTS.with(|c| {
+        let key_pair = UntweakedKeypair::from_seckey_slice(&c.signing, &res.derived_private_key)
+            .expect("Should generate key pair");
+
+        let key_pair = match merkle_root {
+            Some(merkle_root) => key_pair.tap_tweak(&c.verification, merkle_root).to_inner(),
+            None => key_pair,
+        };
+
+        match aux_randomness {
+            Some(aux_rand) => c.signing.sign_schnorr_with_aux_rand(&digest, &key_pair, &aux_rand),
+            None => c.signing.sign_schnorr_no_aux_rand(&digest, &key_pair),
+        }
+    });
 
     SignWithSchnorrReply {
         signature: sig.serialize().to_vec(),
     }
 }
 
+#[ic_cdk::update]
+fn sign_with_ecdsa(arg: SignWithEcdsa) -> SignWithEcdsaReply {
+    let message = arg.message;
+
+    let seed = Seed::new(STATE.with(|s| {
+        s.borrow()
+            .seeds
+            .get(&arg.key_id)
+            .expect(format!("No key with name {:?}", &arg.key_id).as_str())
+            .clone()
+    }));
+
+    let root_xprv = XPrv::new(&seed).unwrap();
+    let private_key_bytes = root_xprv.private_key().to_bytes();
+
+    let master_chain_code = [0u8; 32];
+
+    let canister_id = ic_cdk::caller();
+
+    let mut path = vec![];
+    let derivation_index = DerivationIndex(canister_id.as_slice().to_vec());
+    path.push(derivation_index);
+
+    for index in arg.derivation_path {
+        path.push(DerivationIndex(index));
+    }
+    let derivation_path = DerivationPath::new(path);
+
+    let res = derivation_path
+        .private_key_derivation(&private_key_bytes, &master_chain_code)
+        .expect("Should derive key");
+
+    let digest = Message::from_digest_slice(message.as_ref())
+        .expect("should be cryptographically secure hash");
+
+    let secret_key =
+        SecretKey::from_slice(&res.derived_private_key).expect("Should generate secret key");
+
+    let recoverable_sig =
+        CONTEXTS.with(|c| c.signing.sign_ecdsa_recoverable(&digest, &secret_key));
+
+    let (recovery_id, signature) = recoverable_sig.serialize_compact();
+
+    SignWithEcdsaReply {
+        signature: signature.to_vec(),
+        recovery_id: recovery_id.to_i32(),
+    }
+}
+
+#[ic_cdk::update]
+fn find_derivation_with_prefix(arg: FindDerivationWithPrefix) -> Option<FindDerivationWithPrefixReply> {
+    if arg.max_iters > MAX_VANITY_SEARCH_ITERS {
+        ic_cdk::trap(
+            format!(
+                "max_iters {} exceeds the maximum allowed {}",
+                arg.max_iters, MAX_VANITY_SEARCH_ITERS
+            )
+            .as_str(),
+        );
+    }
+
+    // An empty prefix matches on the first iteration, which would just burn
+    // a derivation for nothing.
+    if arg.prefix.is_empty() {
+        return None;
+    }
+    if arg.prefix.len() > 32 {
+        ic_cdk::trap(
+            format!(
+                "prefix length {} exceeds the 32-byte x-only public key",
+                arg.prefix.len()
+            )
+            .as_str(),
+        );
+    }
+
+    let seed = Seed::new(STATE.with(|s| {
+        s.borrow()
+            .seeds
+            .get(&arg.key_id)
+            .expect(format!("No key with name {:?}", &arg.key_id).as_str())
+            .clone()
+    }));
+
+    let root_xprv = XPrv::new(&seed).unwrap();
+    let key_bytes = root_xprv.private_key().to_bytes();
+
+    let key_pair = CONTEXTS
+        .with(|c| UntweakedKeypair::from_seckey_slice(&c.signing, &key_bytes))
+        .expect("Should generate key pair");
+
+    let master_chain_code = [0u8; 32];
+    let canister_id = ic_cdk::caller();
+    let public_key_sec1 = key_pair.public_key().serialize();
+
+    for i in 0..arg.max_iters {
+        let mut path = vec![DerivationIndex(canister_id.as_slice().to_vec())];
+        for index in &arg.base_path {
+            path.push(DerivationIndex(index.clone()));
+        }
+        path.push(DerivationIndex(i.to_be_bytes().to_vec()));
+        let derivation_path = DerivationPath::new(path);
+
+        let res = derivation_path
+            .key_derivation(&public_key_sec1, &master_chain_code)
+            .expect("Should derive key");
+
+        // `derived_public_key` is a 33-byte SEC1-compressed key; its first
+        // byte is the 0x02/0x03 parity marker, so the x-only key the vanity
+        // search advertises is bytes [1..].
+        if res.derived_public_key[1..].starts_with(&arg.prefix) {
+            return Some(FindDerivationWithPrefixReply {
+                index: i,
+                public_key: res.derived_public_key,
+                chain_code: res.derived_chain_code,
+            });
+        }
+    }
+
+    None
+}
+
 #[ic_cdk::query]
-fn http_request(_req: HttpRequest) -> HttpResponse {
+fn verify_schnorr(arg: VerifySchnorr) -> VerifySchnorrReply {
+    // This endpoint exists to let callers check possibly-bad signatures, so
+    // malformed input must fall through to `is_signature_valid: false`
+    // rather than trapping the call.
+    let is_signature_valid = (|| {
+        let signature = Signature::from_slice(&arg.signature).ok()?;
+        let message = Message::from_digest_slice(arg.message.as_ref()).ok()?;
+        let public_key = parse_xonly_public_key(&arg.public_key)?;
+
+        Some(CONTEXTS.with(|c| {
+            c.verification
+                .verify_schnorr(&signature, &message, &public_key)
+                .is_ok()
+        }))
+    })()
+    .unwrap_or(false);
+
+    VerifySchnorrReply { is_signature_valid }
+}
 
-    let sig_count = STATE.with(|s| s.borrow().sig_count.get().clone());
+/// Parses a public key for [`verify_schnorr`], accepting either a 33-byte
+/// SEC1-compressed key (as derived by `schnorr_public_key`) or a 32-byte
+/// x-only key (as returned for a taproot-tweaked output, see
+/// `SchnorrPublicKey::merkle_root`).
+fn parse_xonly_public_key(bytes: &[u8]) -> Option<XOnlyPublicKey> {
+    if let Ok(public_key) = PublicKey::from_slice(bytes) {
+        return Some(public_key.into());
+    }
+    XOnlyPublicKey::from_slice(bytes).ok()
+}
+
+#[ic_cdk::query]
+fn http_request(req: HttpRequest) -> HttpResponse {
     let balance = ic_cdk::api::canister_balance128();
+
+    let wants_prometheus = req.url.starts_with("/metrics")
+        || req.headers.iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case("accept") && value.contains("text/plain")
+        });
+
+    if wants_prometheus {
+        return HttpResponse {
+            status_code: 200,
+            headers: vec![(
+                "content-type".to_string(),
+                "text/plain; version=0.0.4".to_string(),
+            )],
+            body: ByteBuf::from(render_prometheus_metrics(balance).into_bytes()),
+        };
+    }
+
+    let sig_count = STATE.with(|s| {
+        s.borrow()
+            .sig_count
+            .iter()
+            .map(|(key_id, count)| (key_id.name, count))
+            .collect()
+    });
     let metrics = Metrics { balance, sig_count };
 
     HttpResponse {
@@ -258,9 +577,31 @@ fn http_request(_req: HttpRequest) -> HttpResponse {
     }
 }
 
-fn init_sig_count() -> StableCell<u128, Memory> {
-    StableCell::init(crate::memory::get_sig_count(), 0u128)
-        .expect("Could not initialize sig count memory")
+/// Renders the current metrics in Prometheus exposition format, with the
+/// signature count broken down per `SchnorrKeyId`.
+fn render_prometheus_metrics(balance: u128) -> String {
+    let mut out = String::new();
+
+    out.push_str("# HELP schnorr_signatures_total Total number of Schnorr signatures produced.\n");
+    out.push_str("# TYPE schnorr_signatures_total counter\n");
+    STATE.with(|s| {
+        for (key_id, count) in s.borrow().sig_count.iter() {
+            out.push_str(&format!(
+                "schnorr_signatures_total{{key_id=\"{}\"}} {}\n",
+                key_id.name, count
+            ));
+        }
+    });
+
+    out.push_str("# HELP canister_balance_cycles Cycle balance of the canister.\n");
+    out.push_str("# TYPE canister_balance_cycles gauge\n");
+    out.push_str(&format!("canister_balance_cycles {}\n", balance));
+
+    out
+}
+
+fn init_sig_count() -> StableBTreeMap<SchnorrKeyId, u128, Memory> {
+    StableBTreeMap::init(crate::memory::get_sig_count())
 }
 
 fn init_stable_data() -> StableBTreeMap<SchnorrKeyId, [u8; 64], Memory> {
@@ -290,6 +631,20 @@ async fn get_random_seed() -> [u8; 64] {
     }
 }
 
+/// Fetches 32 bytes of canister-sourced entropy to use as BIP340 auxiliary
+/// randomness, for callers that pass [`AuxRandomness::FromCanister`].
+async fn get_canister_aux_rand() -> [u8; 32] {
+    match ic_cdk::api::management_canister::main::raw_rand().await {
+        Ok(rand) => rand
+            .0
+            .try_into()
+            .expect("raw_rand should return 32 bytes"),
+        Err(err) => {
+            ic_cdk::trap(format!("Error getting random seed: {:?}", err).as_str());
+        }
+    }
+}
+
 pub fn my_custom_random(_buf: &mut [u8]) -> Result<(), Error> {
     ic_cdk::trap("Not implemented");
 }
@@ -0,0 +1,26 @@
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::DefaultMemoryImpl;
+
+use std::cell::RefCell;
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const SEEDS_MEMORY_ID: MemoryId = MemoryId::new(0);
+// MemoryId(1) used to back a `StableCell<u128>` sig count. It is retired,
+// not reused: a `StableBTreeMap` reinterpreting those bytes on
+// `post_upgrade` would trap or corrupt data, so the per-key-id map below
+// gets a fresh id instead.
+const SIG_COUNT_MEMORY_ID: MemoryId = MemoryId::new(2);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+pub fn get_seeds() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(SEEDS_MEMORY_ID))
+}
+
+pub fn get_sig_count() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(SIG_COUNT_MEMORY_ID))
+}